@@ -1,17 +1,24 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
 use std::num::NonZeroUsize;
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
 use lru::LruCache;
+use notify::{EventKind, RecursiveMode, Watcher};
 use parking_lot::Mutex;
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use http_body_util::combinators::BoxBody;
-use hyper::{Request, StatusCode};
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::header::{HeaderValue, CONTENT_TYPE, LOCATION};
+use hyper::{Request, Response, StatusCode};
 use maxminddb::Reader;
 
 use ferron_common::config::ServerConfiguration;
@@ -35,11 +42,370 @@ impl GeoIPMode {
     }
   }
 }
+/// Decoded fields of a `geoip2::City` lookup, cached together so a single MaxMind
+/// lookup per IP can serve geo rule matching as well as header enrichment.
+#[derive(Debug, Clone, Default)]
+struct GeoData {
+  country_code: Option<String>,
+  country_name: Option<String>,
+  city_name: Option<String>,
+  subdivision_code: Option<String>,
+  subdivision_name: Option<String>,
+  continent_code: Option<String>,
+  continent_name: Option<String>,
+  latitude: Option<f64>,
+  longitude: Option<f64>,
+}
+
+fn en_name(names: Option<&std::collections::BTreeMap<&str, &str>>) -> Option<String> {
+  names.and_then(|n| n.get("en")).map(|s| s.to_string())
+}
+
+impl From<maxminddb::geoip2::City<'_>> for GeoData {
+  fn from(city: maxminddb::geoip2::City) -> Self {
+    let subdivision = city.subdivisions.as_ref().and_then(|s| s.last());
+
+    GeoData {
+      country_code: city.country.as_ref().and_then(|c| c.iso_code).map(|c| c.to_ascii_uppercase()),
+      country_name: city.country.as_ref().and_then(|c| en_name(c.names.as_ref())),
+      city_name: city.city.as_ref().and_then(|c| en_name(c.names.as_ref())),
+      subdivision_code: subdivision.and_then(|s| s.iso_code).map(|s| s.to_string()),
+      subdivision_name: subdivision.and_then(|s| en_name(s.names.as_ref())),
+      continent_code: city.continent.as_ref().and_then(|c| c.code).map(|c| c.to_string()),
+      continent_name: city.continent.as_ref().and_then(|c| en_name(c.names.as_ref())),
+      latitude: city.location.as_ref().and_then(|l| l.latitude),
+      longitude: city.location.as_ref().and_then(|l| l.longitude),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum EnrichField {
+  CountryCode,
+  CountryName,
+  City,
+  Subdivision,
+  Continent,
+  Latitude,
+  Longitude,
+}
+
+impl EnrichField {
+  const ALL: [EnrichField; 7] = [
+    EnrichField::CountryCode,
+    EnrichField::CountryName,
+    EnrichField::City,
+    EnrichField::Subdivision,
+    EnrichField::Continent,
+    EnrichField::Latitude,
+    EnrichField::Longitude,
+  ];
+
+  fn from_str(s: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+    match s.to_lowercase().as_str() {
+      "country_code" => Ok(EnrichField::CountryCode),
+      "country_name" => Ok(EnrichField::CountryName),
+      "city" => Ok(EnrichField::City),
+      "subdivision" => Ok(EnrichField::Subdivision),
+      "continent" => Ok(EnrichField::Continent),
+      "latitude" => Ok(EnrichField::Latitude),
+      "longitude" => Ok(EnrichField::Longitude),
+      _ => Err(
+        format!(
+          "Invalid enrich_headers field: {}. Valid fields are: country_code, country_name, city, subdivision, continent, latitude, longitude",
+          s
+        )
+        .into(),
+      ),
+    }
+  }
+
+  fn header_suffix(&self) -> &'static str {
+    match self {
+      EnrichField::CountryCode => "Country-Code",
+      EnrichField::CountryName => "Country-Name",
+      EnrichField::City => "City",
+      EnrichField::Subdivision => "Subdivision",
+      EnrichField::Continent => "Continent",
+      EnrichField::Latitude => "Latitude",
+      EnrichField::Longitude => "Longitude",
+    }
+  }
+
+  fn value(&self, geo: &GeoData) -> Option<String> {
+    match self {
+      EnrichField::CountryCode => geo.country_code.clone(),
+      EnrichField::CountryName => geo.country_name.clone(),
+      EnrichField::City => geo.city_name.clone(),
+      EnrichField::Subdivision => geo.subdivision_name.clone(),
+      EnrichField::Continent => geo.continent_name.clone(),
+      EnrichField::Latitude => geo.latitude.map(|v| v.to_string()),
+      EnrichField::Longitude => geo.longitude.map(|v| v.to_string()),
+    }
+  }
+}
+
+/// Parses a comma-separated list of `enrich_headers` field names, shared between
+/// module loading and configuration validation so both stay in sync.
+fn parse_enrich_fields(s: &str) -> Result<Vec<EnrichField>, Box<dyn Error + Send + Sync>> {
+  s.split(',')
+    .map(|f| f.trim())
+    .filter(|f| !f.is_empty())
+    .map(EnrichField::from_str)
+    .collect()
+}
+
+/// Encodes `value` as a valid HTTP header value, percent-encoding (RFC 8187 style) any
+/// byte `HeaderValue` won't carry as-is. GeoLite2's `"en"`-locale place names are UTF-8
+/// and routinely contain non-ASCII characters ("São Paulo", "Zürich", "Curaçao"), which
+/// `HeaderValue::from_str` rejects outright; falling back to percent-encoding keeps the
+/// enrichment header populated instead of silently dropping it for a large share of
+/// real-world locations.
+fn ascii_safe_header_value(value: &str) -> HeaderValue {
+  if let Ok(header_value) = HeaderValue::from_str(value) {
+    return header_value;
+  }
+
+  let mut encoded = String::with_capacity(value.len());
+  for byte in value.bytes() {
+    match byte {
+      0x20..=0x7e if byte != b'%' => encoded.push(byte as char),
+      _ => encoded.push_str(&format!("%{:02X}", byte)),
+    }
+  }
+
+  HeaderValue::from_str(&encoded).expect("percent-encoded header value is always valid ASCII")
+}
+
+/// Granularity a `countries` rule can target, from least to most specific.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RuleLevel {
+  Continent,
+  Country,
+  Subdivision,
+  City,
+}
+
+/// Most specific first: the first level with both a configured rule set and a
+/// decoded value for the request wins the match in `should_block_country`.
+const RULE_LEVELS_BY_SPECIFICITY: [RuleLevel; 4] =
+  [RuleLevel::City, RuleLevel::Subdivision, RuleLevel::Country, RuleLevel::Continent];
+
+impl RuleLevel {
+  fn from_prefix(s: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+    match s.to_lowercase().as_str() {
+      "continent" => Ok(RuleLevel::Continent),
+      "country" => Ok(RuleLevel::Country),
+      "subdivision" => Ok(RuleLevel::Subdivision),
+      "city" => Ok(RuleLevel::City),
+      _ => Err(
+        format!(
+          "Invalid geoip_filter rule level: {}. Valid levels are: continent, country, subdivision, city",
+          s
+        )
+        .into(),
+      ),
+    }
+  }
+
+  /// Value decoded from the request's geo data, in the same form rules are stored in.
+  /// Subdivisions are qualified with their country (`US-CA`) since MaxMind's
+  /// `subdivisions[].iso_code` is the bare, country-ambiguous code (`CA`) and the
+  /// `subdivision:US-CA` rule syntax is the qualified ISO-3166-2 form.
+  fn geo_value(&self, geo: &GeoData) -> Option<String> {
+    match self {
+      RuleLevel::Continent => geo.continent_code.clone(),
+      RuleLevel::Country => geo.country_code.clone(),
+      RuleLevel::Subdivision => {
+        let country = geo.country_code.as_deref()?;
+        let subdivision = geo.subdivision_code.as_deref()?;
+        Some(format!("{}-{}", country, subdivision))
+      }
+      RuleLevel::City => geo.city_name.as_ref().map(|s| s.to_uppercase()),
+    }
+  }
+}
+
+/// Parses the `countries` property into per-level rule sets, accepting a qualified
+/// `level:value` syntax (`country:US`, `continent:EU`, `subdivision:US-CA`,
+/// `city:London`) alongside the plain `US, GB` form, which is treated as `country:`.
+fn parse_geo_rules(s: &str) -> Result<HashMap<RuleLevel, HashSet<String>>, Box<dyn Error + Send + Sync>> {
+  let mut rules: HashMap<RuleLevel, HashSet<String>> = HashMap::new();
+  for raw in s.split(',') {
+    let raw = raw.trim();
+    if raw.is_empty() {
+      continue;
+    }
+
+    let (level, value) = match raw.split_once(':') {
+      Some((prefix, value)) => (RuleLevel::from_prefix(prefix)?, value.trim()),
+      None => (RuleLevel::Country, raw),
+    };
+
+    if value.is_empty() {
+      return Err(format!("geoip_filter rule '{}' is missing a value", raw).into());
+    }
+
+    rules.entry(level).or_default().insert(value.to_uppercase());
+  }
+  Ok(rules)
+}
+
+/// How a blocked request is answered: a bare status code (the original, empty-body
+/// behavior), a redirect with a `Location` header, or a status code plus an
+/// explanatory body.
+struct BlockResponse {
+  status: StatusCode,
+  redirect: Option<HeaderValue>,
+  body: Option<String>,
+}
+
+impl BlockResponse {
+  fn needs_custom_response(&self) -> bool {
+    self.redirect.is_some() || self.body.is_some()
+  }
+}
+
+/// Parses and validates a `block_status` config value, shared between module loading
+/// and configuration validation so both reject the same out-of-range codes. `code as
+/// u16` alone would silently wrap (e.g. `65899` truncates to `363`, which
+/// `StatusCode::from_u16` happily accepts) instead of surfacing a config error.
+fn parse_block_status(code: i128) -> Result<StatusCode, String> {
+  if !(100..=999).contains(&code) {
+    return Err(format!("Invalid geoip_filter block_status: {}", code));
+  }
+  StatusCode::from_u16(code as u16).map_err(|_| format!("Invalid geoip_filter block_status: {}", code))
+}
+
+fn build_block_response(block: &BlockResponse) -> Response<BoxBody<Bytes, std::io::Error>> {
+  let mut builder = Response::builder().status(block.status);
+
+  if let Some(location) = &block.redirect {
+    builder = builder.header(LOCATION, location.clone());
+  }
+
+  let body: BoxBody<Bytes, std::io::Error> = match &block.body {
+    Some(body) => {
+      builder = builder.header(CONTENT_TYPE, "text/html; charset=utf-8");
+      Full::new(Bytes::from(body.clone()))
+        .map_err(|never: std::convert::Infallible| match never {})
+        .boxed()
+    }
+    None => Empty::new().map_err(|never: std::convert::Infallible| match never {}).boxed(),
+  };
+
+  builder.body(body).expect("geoip_filter block response status and headers are always valid")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decision {
+  Allow,
+  Block,
+}
+
+#[derive(Default)]
+struct CountryCounters {
+  allowed: AtomicU64,
+  blocked: AtomicU64,
+}
+
+const METRICS_SHARD_COUNT: usize = 16;
+const METRICS_MAX_COUNTRIES_PER_SHARD: usize = 64;
+const METRICS_OVERFLOW_COUNTRY: &str = "_other";
+
+/// Per-country, per-decision request counters. Sharded by a hash of the country code
+/// so concurrent requests for different countries rarely contend on the same lock,
+/// and bounded per shard so an attacker can't grow the map unboundedly by spoofing
+/// IPs that resolve to many distinct (fake) countries; once a shard is full, further
+/// unseen countries are folded into an `_other` bucket.
+struct GeoIPMetrics {
+  shards: Vec<Mutex<HashMap<String, CountryCounters>>>,
+}
+
+impl GeoIPMetrics {
+  fn new() -> Self {
+    GeoIPMetrics {
+      shards: (0..METRICS_SHARD_COUNT).map(|_| Mutex::new(HashMap::new())).collect(),
+    }
+  }
+
+  fn shard_index(&self, key: &str) -> usize {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() as usize) % self.shards.len()
+  }
+
+  fn record(&self, country: &str, decision: Decision) {
+    let mut map = self.shards[self.shard_index(country)].lock();
+    let key = if map.contains_key(country) || map.len() < METRICS_MAX_COUNTRIES_PER_SHARD {
+      country
+    } else {
+      METRICS_OVERFLOW_COUNTRY
+    };
+    let counters = map.entry(key.to_string()).or_default();
+    match decision {
+      Decision::Allow => counters.allowed.fetch_add(1, Ordering::Relaxed),
+      Decision::Block => counters.blocked.fetch_add(1, Ordering::Relaxed),
+    };
+  }
+
+  fn render_prometheus(&self) -> String {
+    let mut out = String::from(
+      "# HELP geoip_requests_total Total requests seen by the geoip_filter module, by country and decision.\n\
+       # TYPE geoip_requests_total counter\n",
+    );
+    for shard in &self.shards {
+      for (country, counters) in shard.lock().iter() {
+        let allowed = counters.allowed.load(Ordering::Relaxed);
+        let blocked = counters.blocked.load(Ordering::Relaxed);
+        out.push_str(&format!(
+          "geoip_requests_total{{country=\"{}\",decision=\"allow\"}} {}\n",
+          country, allowed
+        ));
+        out.push_str(&format!(
+          "geoip_requests_total{{country=\"{}\",decision=\"block\"}} {}\n",
+          country, blocked
+        ));
+      }
+    }
+    out
+  }
+}
+
+static METRICS: OnceLock<GeoIPMetrics> = OnceLock::new();
+
+fn metrics() -> &'static GeoIPMetrics {
+  METRICS.get_or_init(GeoIPMetrics::new)
+}
+
+/// Renders the process-wide `geoip_filter` request counters, keyed by country and
+/// allow/block decision, in Prometheus text-exposition format. Shared across every
+/// loaded `geoip_filter` module instance; wire this into a metrics/scrape endpoint.
+pub fn geoip_metrics_prometheus() -> String {
+  metrics().render_prometheus()
+}
+
 struct CacheEntry {
-  country: Option<String>,
+  geo: GeoData,
+  asn: Option<u32>,
   inserted_at: Instant,
 }
 
+/// Parses a comma-separated list of autonomous system numbers, accepting both the
+/// bare (`16509`) and `AS`-prefixed (`AS13335`) forms used by MaxMind and RIR docs.
+fn parse_asn_list(s: &str) -> Result<HashSet<u32>, Box<dyn Error + Send + Sync>> {
+  s.split(',')
+    .map(|entry| entry.trim())
+    .filter(|entry| !entry.is_empty())
+    .map(|entry| {
+      let digits = entry.strip_prefix("AS").or_else(|| entry.strip_prefix("as")).unwrap_or(entry);
+      digits
+        .parse::<u32>()
+        .map_err(|_| format!("Invalid ASN '{}': expected a bare or 'AS'-prefixed integer", entry).into())
+    })
+    .collect()
+}
+
 pub struct GeoIPModuleLoader {
   cache: ModuleCache<GeoIPModule>,
 }
@@ -82,14 +448,10 @@ impl ModuleLoader for GeoIPModuleLoader {
             .and_then(|v| v.as_str())
             .ok_or("Missing geoip_filter countries configuration")?;
 
-          let countries: HashSet<String> = countries_str
-            .split(',')
-            .map(|s| s.trim().to_uppercase())
-            .filter(|s| !s.is_empty())
-            .collect();
+          let rules = parse_geo_rules(countries_str)?;
 
-          if countries.is_empty() {
-            return Err("geoip_filter countries must contain at least one country code".into());
+          if rules.values().all(|set| set.is_empty()) {
+            return Err("geoip_filter countries must contain at least one rule".into());
           }
 
           let allow_unknown = geoip_entry
@@ -105,6 +467,119 @@ impl ModuleLoader for GeoIPModuleLoader {
           let reader = Reader::open_readfile(db_path)
             .map_err(|e| format!("Failed to open MaxMind database at {}: {}", db_path, e))?;
 
+          let watch_db = geoip_entry
+            .and_then(|e| e.props.get("watch_db"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+          let asn_reader = geoip_entry
+            .and_then(|e| e.props.get("asn_db_path"))
+            .and_then(|v| v.as_str())
+            .map(|asn_db_path| {
+              Reader::open_readfile(asn_db_path)
+                .map_err(|e| format!("Failed to open MaxMind ASN database at {}: {}", asn_db_path, e))
+            })
+            .transpose()?;
+
+          let asn_whitelist = geoip_entry
+            .and_then(|e| e.props.get("asn_whitelist"))
+            .and_then(|v| v.as_str())
+            .map(parse_asn_list)
+            .transpose()?
+            .unwrap_or_default();
+
+          let asn_blacklist = geoip_entry
+            .and_then(|e| e.props.get("asn_blacklist"))
+            .and_then(|v| v.as_str())
+            .map(parse_asn_list)
+            .transpose()?
+            .unwrap_or_default();
+
+          let asn_allow_unknown = geoip_entry
+            .and_then(|e| e.props.get("asn_allow_unknown"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+          let enrich_header_prefix = geoip_entry
+            .and_then(|e| e.props.get("enrich_header_prefix"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("X-GeoIP-")
+            .to_string();
+
+          let enrich_fields: Vec<EnrichField> = match geoip_entry.and_then(|e| e.props.get("enrich_headers")) {
+            Some(v) if v.as_bool() == Some(true) => EnrichField::ALL.to_vec(),
+            Some(v) if v.as_bool() == Some(false) => Vec::new(),
+            Some(v) => match v.as_str() {
+              Some(s) => parse_enrich_fields(s)?,
+              None => return Err("The `enrich_headers` property must be a boolean or a comma-separated list of field names".into()),
+            },
+            None => Vec::new(),
+          };
+
+          let enrich_headers: Vec<(EnrichField, hyper::header::HeaderName)> = enrich_fields
+            .into_iter()
+            .map(|field| {
+              let name = format!("{}{}", enrich_header_prefix, field.header_suffix());
+              hyper::header::HeaderName::from_bytes(name.as_bytes())
+                .map(|header_name| (field, header_name))
+                .map_err(|e| format!("Invalid enrich header name '{}': {}", name, e).into())
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error + Send + Sync>>>()?;
+
+          let log_blocked = geoip_entry
+            .and_then(|e| e.props.get("log_blocked"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+
+          let block_redirect = geoip_entry
+            .and_then(|e| e.props.get("block_redirect"))
+            .and_then(|v| v.as_str())
+            .map(|s| {
+              HeaderValue::from_str(s).map_err(|e| format!("Invalid geoip_filter block_redirect value: {}", e))
+            })
+            .transpose()?;
+
+          let block_body_inline = geoip_entry
+            .and_then(|e| e.props.get("block_body"))
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string());
+
+          let block_body_file = geoip_entry.and_then(|e| e.props.get("block_body_file")).and_then(|v| v.as_str());
+
+          if block_body_inline.is_some() && block_body_file.is_some() {
+            return Err("geoip_filter `block_body` and `block_body_file` are mutually exclusive".into());
+          }
+
+          if block_redirect.is_some() && (block_body_inline.is_some() || block_body_file.is_some()) {
+            return Err("geoip_filter `block_redirect` cannot be combined with `block_body`/`block_body_file`".into());
+          }
+
+          let block_body = match block_body_file {
+            Some(path) => Some(
+              std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read geoip_filter block_body_file at {}: {}", path, e))?,
+            ),
+            None => block_body_inline,
+          };
+
+          let block_status_num = geoip_entry.and_then(|e| e.props.get("block_status")).and_then(|v| v.as_i128());
+
+          let block_status = match block_status_num {
+            Some(code) => parse_block_status(code)?,
+            None if block_redirect.is_some() => StatusCode::FOUND,
+            None => StatusCode::FORBIDDEN,
+          };
+
+          if block_redirect.is_some() && !block_status.is_redirection() {
+            return Err("geoip_filter `block_status` must be a 3xx status when `block_redirect` is set".into());
+          }
+
+          let block_response = BlockResponse {
+            status: block_status,
+            redirect: block_redirect,
+            body: block_body,
+          };
+
           let cache_size = geoip_entry
             .and_then(|e| e.props.get("cache_size"))
             .and_then(|v| v.as_i128())
@@ -118,14 +593,27 @@ impl ModuleLoader for GeoIPModuleLoader {
             .max(1) as u64;
           let cache_ttl = Duration::from_secs(cache_ttl_secs);
 
-          let cache = LruCache::new(NonZeroUsize::new(cache_size.max(1)).unwrap());
+          let cache = Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(cache_size.max(1)).unwrap())));
+
+          let reader = Arc::new(ArcSwap::from_pointee(reader));
+
+          if watch_db {
+            spawn_db_watcher(db_path.to_string(), Arc::clone(&reader), Arc::clone(&cache));
+          }
 
           Ok(Arc::new(GeoIPModule {
             mode,
-            countries: Arc::new(countries),
+            rules: Arc::new(rules),
             allow_unknown,
-            reader: Arc::new(reader),
-            cache: Arc::new(Mutex::new(cache)),
+            reader,
+            asn_reader: asn_reader.map(Arc::new),
+            asn_whitelist: Arc::new(asn_whitelist),
+            asn_blacklist: Arc::new(asn_blacklist),
+            asn_allow_unknown,
+            enrich_headers: Arc::new(enrich_headers),
+            log_blocked,
+            block_response: Arc::new(block_response),
+            cache,
             cache_ttl,
           }))
         })?,
@@ -162,9 +650,10 @@ impl ModuleLoader for GeoIPModuleLoader {
         }
 
         if let Some(countries_val) = entry.props.get("countries") {
-          if !countries_val.is_string() {
-            return Err(anyhow::anyhow!("The `countries` property must be a string"))?;
-          }
+          let countries_str = countries_val
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("The `countries` property must be a string"))?;
+          parse_geo_rules(countries_str)?;
         } else {
           return Err(anyhow::anyhow!(
             "The `countries` property is required in geoip_filter configuration"
@@ -177,6 +666,109 @@ impl ModuleLoader for GeoIPModuleLoader {
           }
         }
 
+        if let Some(watch_db_val) = entry.props.get("watch_db") {
+          if !watch_db_val.is_bool() {
+            return Err(anyhow::anyhow!("The `watch_db` property must be a boolean"))?;
+          }
+        }
+
+        if let Some(log_blocked_val) = entry.props.get("log_blocked") {
+          if !log_blocked_val.is_bool() {
+            return Err(anyhow::anyhow!("The `log_blocked` property must be a boolean"))?;
+          }
+        }
+
+        let block_status = match entry.props.get("block_status") {
+          Some(block_status_val) => {
+            let code = block_status_val
+              .as_i128()
+              .ok_or_else(|| anyhow::anyhow!("The `block_status` property must be an integer"))?;
+            Some(parse_block_status(code).map_err(|e| anyhow::anyhow!(e))?)
+          }
+          None => None,
+        };
+
+        let block_redirect_val = entry.props.get("block_redirect");
+        if let Some(v) = block_redirect_val {
+          let block_redirect_str = v
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("The `block_redirect` property must be a string"))?;
+          HeaderValue::from_str(block_redirect_str)
+            .map_err(|e| anyhow::anyhow!("The `block_redirect` property is not a valid header value: {}", e))?;
+        }
+
+        let block_body_val = entry.props.get("block_body");
+        if let Some(v) = block_body_val {
+          if !v.is_string() {
+            return Err(anyhow::anyhow!("The `block_body` property must be a string"))?;
+          }
+        }
+
+        let block_body_file_val = entry.props.get("block_body_file");
+        if let Some(v) = block_body_file_val {
+          if !v.is_string() {
+            return Err(anyhow::anyhow!("The `block_body_file` property must be a string"))?;
+          }
+        }
+
+        if block_body_val.is_some() && block_body_file_val.is_some() {
+          return Err(anyhow::anyhow!(
+            "The `block_body` and `block_body_file` properties are mutually exclusive"
+          ))?;
+        }
+
+        if block_redirect_val.is_some() && (block_body_val.is_some() || block_body_file_val.is_some()) {
+          return Err(anyhow::anyhow!(
+            "The `block_redirect` property cannot be combined with `block_body`/`block_body_file`"
+          ))?;
+        }
+
+        if block_redirect_val.is_some() {
+          if let Some(status) = block_status {
+            if !status.is_redirection() {
+              return Err(anyhow::anyhow!(
+                "The `block_status` property must be a 3xx status when `block_redirect` is set"
+              ))?;
+            }
+          }
+        }
+
+        if let Some(asn_db_path_val) = entry.props.get("asn_db_path") {
+          if !asn_db_path_val.is_string() {
+            return Err(anyhow::anyhow!("The `asn_db_path` property must be a string"))?;
+          }
+        }
+
+        for asn_prop in ["asn_whitelist", "asn_blacklist"] {
+          if let Some(asn_list_val) = entry.props.get(asn_prop) {
+            let asn_list_str = asn_list_val
+              .as_str()
+              .ok_or_else(|| anyhow::anyhow!("The `{}` property must be a string", asn_prop))?;
+            parse_asn_list(asn_list_str)?;
+          }
+        }
+
+        if let Some(asn_allow_unknown_val) = entry.props.get("asn_allow_unknown") {
+          if !asn_allow_unknown_val.is_bool() {
+            return Err(anyhow::anyhow!("The `asn_allow_unknown` property must be a boolean"))?;
+          }
+        }
+
+        if let Some(enrich_header_prefix_val) = entry.props.get("enrich_header_prefix") {
+          if !enrich_header_prefix_val.is_string() {
+            return Err(anyhow::anyhow!("The `enrich_header_prefix` property must be a string"))?;
+          }
+        }
+
+        if let Some(enrich_headers_val) = entry.props.get("enrich_headers") {
+          if !enrich_headers_val.is_bool() {
+            let enrich_headers_str = enrich_headers_val
+              .as_str()
+              .ok_or_else(|| anyhow::anyhow!("The `enrich_headers` property must be a boolean or a string"))?;
+            parse_enrich_fields(enrich_headers_str)?;
+          }
+        }
+
         if let Some(db_path_val) = entry.props.get("db_path") {
           if !db_path_val.is_string() {
             return Err(anyhow::anyhow!("The `db_path` property must be a string"))?;
@@ -216,9 +808,16 @@ impl ModuleLoader for GeoIPModuleLoader {
 
 struct GeoIPModule {
   mode: GeoIPMode,
-  countries: Arc<HashSet<String>>,
+  rules: Arc<HashMap<RuleLevel, HashSet<String>>>,
   allow_unknown: bool,
-  reader: Arc<Reader<Vec<u8>>>,
+  reader: Arc<ArcSwap<Reader<Vec<u8>>>>,
+  asn_reader: Option<Arc<Reader<Vec<u8>>>>,
+  asn_whitelist: Arc<HashSet<u32>>,
+  asn_blacklist: Arc<HashSet<u32>>,
+  asn_allow_unknown: bool,
+  enrich_headers: Arc<Vec<(EnrichField, hyper::header::HeaderName)>>,
+  log_blocked: bool,
+  block_response: Arc<BlockResponse>,
   cache: Arc<Mutex<LruCache<IpAddr, CacheEntry>>>,
   cache_ttl: Duration,
 }
@@ -227,9 +826,16 @@ impl Module for GeoIPModule {
   fn get_module_handlers(&self) -> Box<dyn ModuleHandlers> {
     Box::new(GeoIPModuleHandlers {
       mode: self.mode.clone(),
-      countries: Arc::clone(&self.countries),
+      rules: Arc::clone(&self.rules),
       allow_unknown: self.allow_unknown,
       reader: Arc::clone(&self.reader),
+      asn_reader: self.asn_reader.clone(),
+      asn_whitelist: Arc::clone(&self.asn_whitelist),
+      asn_blacklist: Arc::clone(&self.asn_blacklist),
+      asn_allow_unknown: self.asn_allow_unknown,
+      enrich_headers: Arc::clone(&self.enrich_headers),
+      log_blocked: self.log_blocked,
+      block_response: Arc::clone(&self.block_response),
       cache: Arc::clone(&self.cache),
       cache_ttl: self.cache_ttl,
     })
@@ -238,85 +844,257 @@ impl Module for GeoIPModule {
 
 struct GeoIPModuleHandlers {
   mode: GeoIPMode,
-  countries: Arc<HashSet<String>>,
+  rules: Arc<HashMap<RuleLevel, HashSet<String>>>,
   allow_unknown: bool,
-  reader: Arc<Reader<Vec<u8>>>,
+  reader: Arc<ArcSwap<Reader<Vec<u8>>>>,
+  asn_reader: Option<Arc<Reader<Vec<u8>>>>,
+  asn_whitelist: Arc<HashSet<u32>>,
+  asn_blacklist: Arc<HashSet<u32>>,
+  asn_allow_unknown: bool,
+  enrich_headers: Arc<Vec<(EnrichField, hyper::header::HeaderName)>>,
+  log_blocked: bool,
+  block_response: Arc<BlockResponse>,
   cache: Arc<Mutex<LruCache<IpAddr, CacheEntry>>>,
   cache_ttl: Duration,
 }
 
 impl GeoIPModuleHandlers {
-  fn lookup_country_cached(&self, ip: IpAddr) -> Option<String> {
+  fn lookup_geo_cached(&self, ip: IpAddr) -> (GeoData, Option<u32>) {
     let now = Instant::now();
     let mut cache = self.cache.lock();
     if let Some(entry) = cache.get(&ip) {
       if now.duration_since(entry.inserted_at) <= self.cache_ttl {
-        return entry.country.clone();
+        return (entry.geo.clone(), entry.asn);
       }
       cache.pop(&ip);
     }
+    drop(cache);
 
-    let country = self
+    let geo = self
       .reader
+      .load()
       .lookup(ip)
       .ok()
       .and_then(|r| r.decode::<maxminddb::geoip2::City>().ok())
       .flatten()
-      .and_then(|c| c.country.iso_code.map(|c| c.to_ascii_uppercase()));
+      .map(GeoData::from)
+      .unwrap_or_default();
+
+    let asn = self.asn_reader.as_ref().and_then(|asn_reader| {
+      asn_reader
+        .lookup(ip)
+        .ok()
+        .and_then(|r| r.decode::<maxminddb::geoip2::Asn>().ok())
+        .flatten()
+        .and_then(|a| a.autonomous_system_number)
+    });
 
     self.cache.lock().put(
       ip,
       CacheEntry {
-        country: country.clone(),
+        geo: geo.clone(),
+        asn,
         inserted_at: now,
       },
     );
 
-    country
+    (geo, asn)
+  }
+
+  /// Finds the most specific rule level that has both configured rules and a decoded
+  /// value for this request, and reports whether that value is a member of its set.
+  fn matching_rule(&self, geo: &GeoData) -> Option<bool> {
+    RULE_LEVELS_BY_SPECIFICITY.iter().find_map(|level| {
+      let set = self.rules.get(level)?;
+      let value = level.geo_value(geo)?;
+      Some(set.contains(&value))
+    })
   }
-  fn should_block(&self, country: Option<&str>) -> bool {
-    match country {
-      Some(code) => match self.mode {
-        GeoIPMode::Whitelist => !self.countries.contains(code),
-        GeoIPMode::Blacklist => self.countries.contains(code),
+
+  fn should_block_country(&self, geo: &GeoData) -> bool {
+    match self.matching_rule(geo) {
+      Some(is_member) => match self.mode {
+        GeoIPMode::Whitelist => !is_member,
+        GeoIPMode::Blacklist => is_member,
       },
       None => !self.allow_unknown,
     }
   }
+
+  /// Blocks if the ASN is explicitly blacklisted, or if a whitelist is configured and
+  /// the ASN isn't on it. Has no opinion when no ASN database is configured at all.
+  fn should_block_asn(&self, asn: Option<u32>) -> bool {
+    if self.asn_reader.is_none() {
+      return false;
+    }
+    asn_block_decision(asn, &self.asn_whitelist, &self.asn_blacklist, self.asn_allow_unknown)
+  }
+}
+
+/// Whitelist/blacklist decision for a resolved (or unresolved) ASN, split out from
+/// `should_block_asn` so it's unit-testable without a real MaxMind ASN database.
+/// An ASN that fails to resolve (private/CGNAT ranges, unmapped space) is treated
+/// like an unknown country: blocked by default when a whitelist is configured, same
+/// as `allow_unknown` does for `should_block_country`, unless `asn_allow_unknown` says
+/// otherwise.
+fn asn_block_decision(asn: Option<u32>, whitelist: &HashSet<u32>, blacklist: &HashSet<u32>, allow_unknown: bool) -> bool {
+  match asn {
+    Some(number) => {
+      if blacklist.contains(&number) {
+        return true;
+      }
+      !whitelist.is_empty() && !whitelist.contains(&number)
+    }
+    None => !whitelist.is_empty() && !allow_unknown,
+  }
+}
+
+/// Whether a (possibly erroring) `notify` event is a create/modify/remove event that
+/// touches `file_name`. Split out from `spawn_db_watcher` so the matching logic is
+/// unit-testable without a real filesystem watcher.
+fn event_matches_watched_file(event: &notify::Result<notify::Event>, file_name: &std::ffi::OsStr) -> bool {
+  match event {
+    Ok(e) => {
+      matches!(e.kind, EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_))
+        && e.paths.iter().any(|p| p.file_name() == Some(file_name))
+    }
+    Err(_) => false,
+  }
+}
+
+/// Watches the directory containing `db_path` for changes to that file and atomically
+/// swaps in a freshly opened `Reader` on `reader`, flushing `cache` so stale answers
+/// from the old database aren't served. Watches the *parent directory* rather than
+/// the file itself: MaxMind updates land via atomic `rename(2)`, which replaces the
+/// watched file's inode outright, and a watch on the file path stops delivering events
+/// once that inode is gone — only the first update after process start would ever
+/// reload. Watching the directory and filtering by file name survives every
+/// subsequent rename. Runs for the lifetime of the process; if the watcher itself
+/// fails to start, the module keeps serving the database it already loaded.
+fn spawn_db_watcher(
+  db_path: String,
+  reader: Arc<ArcSwap<Reader<Vec<u8>>>>,
+  cache: Arc<Mutex<LruCache<IpAddr, CacheEntry>>>,
+) {
+  std::thread::spawn(move || {
+    let path = Path::new(&db_path);
+    let (watch_dir, file_name) = match (path.parent(), path.file_name()) {
+      (Some(dir), Some(name)) => (if dir.as_os_str().is_empty() { Path::new(".") } else { dir }, name.to_os_string()),
+      _ => {
+        eprintln!("geoip_filter: cannot determine parent directory of database path {}", db_path);
+        return;
+      }
+    };
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(tx) {
+      Ok(watcher) => watcher,
+      Err(e) => {
+        eprintln!("geoip_filter: failed to start database watcher for {}: {}", db_path, e);
+        return;
+      }
+    };
+
+    if let Err(e) = watcher.watch(watch_dir, RecursiveMode::NonRecursive) {
+      eprintln!(
+        "geoip_filter: failed to watch directory {} for database reload: {}",
+        watch_dir.display(),
+        e
+      );
+      return;
+    }
+
+    let debounce = Duration::from_secs(1);
+
+    while let Ok(event) = rx.recv() {
+      if !event_matches_watched_file(&event, &file_name) {
+        continue;
+      }
+
+      // Coalesce any further events (editors/atomic renames fire several) within the debounce window.
+      while rx.recv_timeout(debounce).is_ok() {}
+
+      match Reader::open_readfile(&db_path) {
+        Ok(new_reader) => {
+          reader.store(Arc::new(new_reader));
+          cache.lock().clear();
+        }
+        Err(e) => {
+          eprintln!(
+            "geoip_filter: failed to reload database at {}: {}, keeping previous database",
+            db_path, e
+          );
+        }
+      }
+    }
+  });
 }
 
 #[async_trait(?Send)]
 impl ModuleHandlers for GeoIPModuleHandlers {
   async fn request_handler(
     &mut self,
-    request: Request<BoxBody<Bytes, std::io::Error>>,
+    mut request: Request<BoxBody<Bytes, std::io::Error>>,
     _config: &ServerConfiguration,
     socket_data: &SocketData,
     error_logger: &ErrorLogger,
   ) -> Result<ResponseData, Box<dyn Error + Send + Sync>> {
     let ip = socket_data.remote_addr.ip().to_canonical();
 
-    let country = self.lookup_country_cached(ip);
+    let (geo, asn) = self.lookup_geo_cached(ip);
 
-    if self.should_block(country.as_deref()) {
-      error_logger
-        .log(&format!(
-          "GeoIP blocked request from IP {} (Country: {}, Mode: {:?}, AllowUnknown: {})",
-          ip,
-          country.as_deref().unwrap_or("Unknown"),
-          self.mode,
-          self.allow_unknown
-        ))
-        .await;
+    // A request is blocked if either the country rule or the ASN rule says block.
+    let blocked = self.should_block_country(&geo) || self.should_block_asn(asn);
+    let country_key = geo.country_code.clone().unwrap_or_else(|| "XX".to_string());
+    metrics().record(&country_key, if blocked { Decision::Block } else { Decision::Allow });
 
-      Ok(ResponseData {
-        request: Some(request),
-        response: None,
-        response_status: Some(StatusCode::FORBIDDEN),
-        response_headers: None,
-        new_remote_address: None,
-      })
+    if blocked {
+      if self.log_blocked {
+        error_logger
+          .log(&format!(
+            "GeoIP blocked request from IP {} (Country: {}, ASN: {}, Mode: {:?}, AllowUnknown: {})",
+            ip,
+            geo.country_code.as_deref().unwrap_or("Unknown"),
+            asn.map(|n| n.to_string()).as_deref().unwrap_or("Unknown"),
+            self.mode,
+            self.allow_unknown
+          ))
+          .await;
+      }
+
+      if self.block_response.needs_custom_response() {
+        Ok(ResponseData {
+          request: Some(request),
+          response: Some(build_block_response(&self.block_response)),
+          response_status: None,
+          response_headers: None,
+          new_remote_address: None,
+        })
+      } else {
+        Ok(ResponseData {
+          request: Some(request),
+          response: None,
+          response_status: Some(self.block_response.status),
+          response_headers: None,
+          new_remote_address: None,
+        })
+      }
     } else {
+      if !self.enrich_headers.is_empty() {
+        let headers = request.headers_mut();
+        for (field, header_name) in self.enrich_headers.iter() {
+          // Always insert or remove the header ourselves, overwriting any client-supplied value of the same name.
+          match field.value(&geo).map(|v| ascii_safe_header_value(&v)) {
+            Some(header_value) => {
+              headers.insert(header_name, header_value);
+            }
+            None => {
+              headers.remove(header_name);
+            }
+          }
+        }
+      }
+
       Ok(ResponseData {
         request: Some(request),
         response: None,
@@ -327,3 +1105,183 @@ impl ModuleHandlers for GeoIPModuleHandlers {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use super::*;
+
+  #[test]
+  fn subdivision_rule_matches_country_qualified_iso_code() {
+    let geo = GeoData {
+      country_code: Some("US".to_string()),
+      subdivision_code: Some("CA".to_string()),
+      ..Default::default()
+    };
+
+    assert_eq!(RuleLevel::Subdivision.geo_value(&geo), Some("US-CA".to_string()));
+
+    let rules = parse_geo_rules("subdivision:US-CA").unwrap();
+    assert!(rules[&RuleLevel::Subdivision].contains("US-CA"));
+  }
+
+  #[test]
+  fn subdivision_rule_does_not_match_bare_code_from_another_country() {
+    // A bare "CA" (Canada) decode must not accidentally match a "US-CA" rule.
+    let geo = GeoData {
+      country_code: Some("CA".to_string()),
+      subdivision_code: Some("CA".to_string()),
+      ..Default::default()
+    };
+
+    assert_eq!(RuleLevel::Subdivision.geo_value(&geo), Some("CA-CA".to_string()));
+  }
+
+  fn watched_event(kind: EventKind, path: &str) -> notify::Result<notify::Event> {
+    Ok(notify::Event::new(kind).add_path(PathBuf::from(path)))
+  }
+
+  #[test]
+  fn event_filter_matches_repeated_renames_of_the_watched_file() {
+    let file_name = std::ffi::OsStr::new("GeoLite2-Country.mmdb");
+
+    // Simulate two independent atomic-rename update cycles for the same filename.
+    // Watching the parent directory (rather than the file path itself) means
+    // neither cycle invalidates the watch, so both must be recognized.
+    for _ in 0..2 {
+      let remove = watched_event(
+        EventKind::Remove(notify::event::RemoveKind::Any),
+        "/data/GeoLite2-Country.mmdb",
+      );
+      let create = watched_event(
+        EventKind::Create(notify::event::CreateKind::Any),
+        "/data/GeoLite2-Country.mmdb",
+      );
+      assert!(event_matches_watched_file(&remove, file_name));
+      assert!(event_matches_watched_file(&create, file_name));
+    }
+
+    let unrelated = watched_event(EventKind::Modify(notify::event::ModifyKind::Any), "/data/other-file.txt");
+    assert!(!event_matches_watched_file(&unrelated, file_name));
+  }
+
+  #[test]
+  fn parse_asn_list_accepts_bare_and_as_prefixed_forms() {
+    let asns = parse_asn_list("16509, AS13335, as15169").unwrap();
+    assert_eq!(asns, HashSet::from([16509, 13335, 15169]));
+  }
+
+  #[test]
+  fn asn_whitelist_blocks_unresolved_asn_by_default() {
+    let whitelist = HashSet::from([13335]);
+    let blacklist = HashSet::new();
+
+    // An ASN that failed to resolve must not sail through a whitelist-based policy.
+    assert!(asn_block_decision(None, &whitelist, &blacklist, false));
+    // Explicitly opting into asn_allow_unknown restores the old fail-open behavior.
+    assert!(!asn_block_decision(None, &whitelist, &blacklist, true));
+  }
+
+  #[test]
+  fn asn_without_any_whitelist_never_blocks_unresolved_asn() {
+    let whitelist = HashSet::new();
+    let blacklist = HashSet::from([13335]);
+
+    // No whitelist configured means there's nothing to fail open on.
+    assert!(!asn_block_decision(None, &whitelist, &blacklist, false));
+  }
+
+  #[test]
+  fn asn_blacklist_blocks_matching_resolved_asn() {
+    let whitelist = HashSet::new();
+    let blacklist = HashSet::from([13335]);
+
+    assert!(asn_block_decision(Some(13335), &whitelist, &blacklist, false));
+    assert!(!asn_block_decision(Some(15169), &whitelist, &blacklist, false));
+  }
+
+  #[test]
+  fn parse_enrich_fields_parses_known_field_names() {
+    let fields = parse_enrich_fields("country_code, city,subdivision").unwrap();
+    assert_eq!(fields, vec![EnrichField::CountryCode, EnrichField::City, EnrichField::Subdivision]);
+
+    assert!(parse_enrich_fields("not_a_real_field").is_err());
+  }
+
+  #[test]
+  fn ascii_safe_header_value_percent_encodes_non_ascii_place_names() {
+    let header_value = ascii_safe_header_value("São Paulo");
+    assert_eq!(header_value.to_str().unwrap(), "S%C3%A3o Paulo");
+
+    // Plain ASCII values pass through untouched.
+    let ascii_value = ascii_safe_header_value("London");
+    assert_eq!(ascii_value.to_str().unwrap(), "London");
+  }
+
+  #[test]
+  fn parse_block_status_rejects_out_of_range_codes() {
+    // 65899 as u16 wraps to 363, which `StatusCode::from_u16` would otherwise accept.
+    assert!(parse_block_status(65899).is_err());
+    assert!(parse_block_status(99).is_err());
+    assert!(parse_block_status(1000).is_err());
+
+    assert_eq!(parse_block_status(404).unwrap(), StatusCode::NOT_FOUND);
+  }
+
+  #[test]
+  fn build_block_response_sets_status_and_redirect_location() {
+    let block = BlockResponse {
+      status: StatusCode::FOUND,
+      redirect: Some(HeaderValue::from_static("https://example.com/blocked")),
+      body: None,
+    };
+
+    let response = build_block_response(&block);
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(response.headers().get(LOCATION).unwrap(), "https://example.com/blocked");
+  }
+
+  #[test]
+  fn build_block_response_sets_content_type_when_body_present() {
+    let block = BlockResponse {
+      status: StatusCode::FORBIDDEN,
+      redirect: None,
+      body: Some("<html>blocked</html>".to_string()),
+    };
+
+    let response = build_block_response(&block);
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "text/html; charset=utf-8");
+  }
+
+  #[test]
+  fn metrics_render_prometheus_reports_allow_and_block_counts() {
+    let metrics = GeoIPMetrics::new();
+    metrics.record("US", Decision::Allow);
+    metrics.record("US", Decision::Allow);
+    metrics.record("US", Decision::Block);
+
+    let rendered = metrics.render_prometheus();
+    assert!(rendered.contains("geoip_requests_total{country=\"US\",decision=\"allow\"} 2"));
+    assert!(rendered.contains("geoip_requests_total{country=\"US\",decision=\"block\"} 1"));
+  }
+
+  #[test]
+  fn metrics_overflow_bucket_catches_countries_past_the_shard_cap() {
+    // Force every one of these into the same shard's overflow bucket by using a
+    // single-shard metrics instance sized below the number of distinct keys.
+    let single_shard = GeoIPMetrics {
+      shards: vec![Mutex::new(HashMap::new())],
+    };
+    for i in 0..(METRICS_MAX_COUNTRIES_PER_SHARD + 1) {
+      single_shard.record(&format!("C{}", i), Decision::Allow);
+    }
+
+    let rendered = single_shard.render_prometheus();
+    assert!(rendered.contains(&format!(
+      "geoip_requests_total{{country=\"{}\",decision=\"allow\"}}",
+      METRICS_OVERFLOW_COUNTRY
+    )));
+  }
+}